@@ -2,9 +2,14 @@ use anyhow::{anyhow, Context as _, Result};
 use arrayvec::ArrayString;
 use completion::LanguageModelCompletionProvider;
 use fs::Fs;
-use futures::{stream::StreamExt, TryFutureExt};
+use futures::{
+    future::{self, BoxFuture, Either},
+    select_biased,
+    stream::StreamExt,
+    FutureExt as _, TryFutureExt,
+};
 use futures_batch::ChunksTimeoutStreamExt;
-use gpui::{AppContext, Model, Task};
+use gpui::{AppContext, AsyncAppContext, Model, Task};
 use heed::{
     types::{SerdeBincode, Str},
     RoTxn,
@@ -16,12 +21,18 @@ use language_model::{
 use log;
 use parking_lot::Mutex;
 use project::{Entry, UpdatedEntriesSet, Worktree};
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
+use settings::{Settings, SettingsSources, SettingsStore};
 use smol::channel;
 use std::{
+    collections::{HashMap, HashSet},
     future::Future,
-    path::Path,
-    sync::Arc,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
     time::{Duration, Instant, SystemTime},
 };
 use util::ResultExt;
@@ -50,7 +61,7 @@ struct UnsummarizedFile {
 #[derive(Debug, Serialize, Deserialize)]
 struct SummarizedFile {
     // Path to the file on disk
-    path: String,
+    path: Arc<Path>,
     // The mtime of the file on disk
     mtime: Option<SystemTime>,
     // BLAKE3 hash of the source file's contents
@@ -62,6 +73,287 @@ struct SummarizedFile {
 /// This is what blake3's to_hex() method returns - see https://docs.rs/blake3/1.5.3/src/blake3/lib.rs.html#246
 pub type Blake3Digest = ArrayString<{ blake3::OUT_LEN * 2 }>;
 
+/// How often the ref-count GC worker wakes up to look for unreferenced summaries.
+const GC_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+/// How long a digest's ref count must stay at zero before its summary is actually deleted.
+/// This gives a file that's briefly renamed or touched (dropping to zero and then back up)
+/// a chance to reuse the cached summary instead of re-requesting it from the model.
+const GC_TOMBSTONE_DELAY: Duration = Duration::from_secs(60 * 60);
+
+/// Summaries smaller than this stay uncompressed, since zstd's per-entry overhead isn't
+/// worth paying for a summary that's only a sentence or two.
+const INLINE_THRESHOLD: usize = 3 * 1024;
+
+const ZSTD_COMPRESSION_LEVEL: i32 = 3;
+
+/// Rough budget (in bytes) for how much source a single summarization prompt sends to the
+/// model. Files larger than this are split into chunks and summarized via map-reduce instead
+/// of being sent in one request.
+const CHUNK_BYTE_BUDGET: usize = 24_000;
+
+/// How many trailing lines of one chunk are repeated at the start of the next, so a summary
+/// doesn't lose context for things that span a chunk boundary.
+const CHUNK_OVERLAP_LINES: usize = 3;
+
+/// Bumped whenever the summarization prompt template changes in a way that would make older
+/// summaries meaningfully different from what the current prompt produces, so the resync
+/// worker migrates them even if the model itself hasn't changed.
+const PROMPT_VERSION: u32 = 1;
+
+/// Fallback model used until the user configures `summarization.model` in their settings.
+const DEFAULT_SUMMARIZATION_MODEL: &str = "gpt-4o-mini";
+
+/// How often the resync worker checks whether the configured summarization model has changed,
+/// in case the `SettingsStore` observer in `spawn_resync_worker` was added after a change
+/// already landed.
+const RESYNC_CHECK_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+/// Pause between resyncing individual cached summaries, so this low-priority background
+/// migration doesn't compete with normal indexing for model requests.
+const RESYNC_ENTRY_DELAY: Duration = Duration::from_millis(500);
+
+/// Settings controlling which language model produces file summaries for semantic search.
+/// Changing `model` causes the resync worker to re-summarize every cached entry that was
+/// produced by a different model, migrating the cache over to the new one in the background.
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
+pub struct SummarizationSettings {
+    #[serde(default = "default_summarization_model")]
+    pub model: String,
+}
+
+fn default_summarization_model() -> String {
+    DEFAULT_SUMMARIZATION_MODEL.to_string()
+}
+
+impl Default for SummarizationSettings {
+    fn default() -> Self {
+        Self {
+            model: default_summarization_model(),
+        }
+    }
+}
+
+impl Settings for SummarizationSettings {
+    const KEY: Option<&'static str> = Some("summarization");
+
+    type FileContent = Self;
+
+    fn load(sources: SettingsSources<Self::FileContent>, _cx: &mut AppContext) -> Result<Self> {
+        sources.json_merge()
+    }
+}
+
+
+/// A running count of an `IndexingJob`'s progress through the scan -> digest -> summarize ->
+/// persist pipeline, suitable for driving a progress bar in the UI.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct IndexingProgress {
+    pub files_discovered: u64,
+    pub files_hashed: u64,
+    pub summaries_completed: u64,
+    pub bytes_processed: u64,
+}
+
+/// Tracks progress across the pipeline's concurrent stages and reports snapshots over a
+/// channel. Cloned into each stage; all clones share the same counters.
+#[derive(Clone)]
+struct ProgressTracker {
+    files_discovered: Arc<AtomicU64>,
+    files_hashed: Arc<AtomicU64>,
+    summaries_completed: Arc<AtomicU64>,
+    bytes_processed: Arc<AtomicU64>,
+    tx: channel::Sender<IndexingProgress>,
+}
+
+impl ProgressTracker {
+    fn new() -> (Self, channel::Receiver<IndexingProgress>) {
+        let (tx, rx) = channel::unbounded();
+        let this = Self {
+            files_discovered: Arc::new(AtomicU64::new(0)),
+            files_hashed: Arc::new(AtomicU64::new(0)),
+            summaries_completed: Arc::new(AtomicU64::new(0)),
+            bytes_processed: Arc::new(AtomicU64::new(0)),
+            tx,
+        };
+        (this, rx)
+    }
+
+    fn snapshot(&self) -> IndexingProgress {
+        IndexingProgress {
+            files_discovered: self.files_discovered.load(Ordering::Relaxed),
+            files_hashed: self.files_hashed.load(Ordering::Relaxed),
+            summaries_completed: self.summaries_completed.load(Ordering::Relaxed),
+            bytes_processed: self.bytes_processed.load(Ordering::Relaxed),
+        }
+    }
+
+    async fn file_discovered(&self) {
+        self.files_discovered.fetch_add(1, Ordering::Relaxed);
+        self.emit().await;
+    }
+
+    async fn file_hashed(&self, bytes: u64) {
+        self.files_hashed.fetch_add(1, Ordering::Relaxed);
+        self.bytes_processed.fetch_add(bytes, Ordering::Relaxed);
+        self.emit().await;
+    }
+
+    async fn summary_completed(&self) {
+        self.summaries_completed.fetch_add(1, Ordering::Relaxed);
+        self.emit().await;
+    }
+
+    async fn emit(&self) {
+        // Progress is best-effort: if nobody's listening anymore, that's fine.
+        let _ = self.tx.send(self.snapshot()).await;
+    }
+}
+
+/// A cooperative cancellation flag, checked between items by the `digest_files` and
+/// `summarize_files` stages so an in-flight `IndexingJob` can be stopped with low latency. The
+/// scan, cache-check and persist stages do not check it; they wind down once their input
+/// channels close or their output channels are dropped.
+#[derive(Clone, Default)]
+pub struct CancellationToken(Arc<AtomicBool>);
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+/// A handle to an in-progress (or completed) indexing run: the scan -> digest -> cache ->
+/// summarize -> persist pipeline, wrapped so callers can observe progress and cancel mid-run.
+/// Dropping the job drops its task, which aborts the pipeline at its next await point; to stop
+/// it cooperatively, call `cancel`, then `join` to wait for the in-flight work to wind down.
+pub struct IndexingJob {
+    pub progress: channel::Receiver<IndexingProgress>,
+    pub cancellation: CancellationToken,
+    task: Task<Result<()>>,
+}
+
+impl IndexingJob {
+    pub fn cancel(&self) {
+        self.cancellation.cancel();
+    }
+
+    pub async fn join(self) -> Result<()> {
+        self.task.await
+    }
+}
+
+/// Splits `code` into chunks of at most `budget` bytes, always breaking on a line boundary,
+/// with `overlap_lines` lines of overlap between adjacent chunks. A single line longer than
+/// `budget` becomes its own (oversized) chunk rather than being split mid-line.
+fn split_into_chunks(code: &str, budget: usize, overlap_lines: usize) -> Vec<String> {
+    let lines: Vec<&str> = code.lines().collect();
+    if lines.is_empty() {
+        return Vec::new();
+    }
+
+    let mut chunks = Vec::new();
+    let mut start = 0;
+
+    while start < lines.len() {
+        let mut end = start;
+        let mut len = 0;
+
+        while end < lines.len() {
+            let line_len = lines[end].len() + 1;
+            if end > start && len + line_len > budget {
+                break;
+            }
+            len += line_len;
+            end += 1;
+        }
+
+        chunks.push(lines[start..end].join("\n"));
+
+        if end == lines.len() {
+            break;
+        }
+
+        start = end.saturating_sub(overlap_lines).max(start + 1);
+    }
+
+    chunks
+}
+
+/// Truncates `text` to at most `budget` bytes, backing off to the nearest UTF-8 char boundary
+/// rather than risking a panic by slicing mid-codepoint.
+fn truncate_to_byte_budget(text: &str, budget: usize) -> &str {
+    if text.len() <= budget {
+        return text;
+    }
+
+    let mut end = budget;
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+    &text[..end]
+}
+
+/// The value stored in `summary_db`: the summary text plus which model/prompt produced it, so
+/// the resync worker can tell a stale entry apart from a current one.
+#[derive(Debug, Serialize, Deserialize)]
+struct StoredSummary {
+    payload: SummaryPayload,
+    model_id: String,
+    prompt_version: u32,
+}
+
+/// A small tagged frame so that short summaries can stay uncompressed (avoiding zstd's fixed
+/// per-entry overhead) while larger ones are compressed.
+#[derive(Debug, Serialize, Deserialize)]
+enum SummaryPayload {
+    Raw(String),
+    Zstd(Vec<u8>),
+}
+
+impl StoredSummary {
+    fn compress(summary: &str, model_id: String) -> Result<Self> {
+        let payload = if summary.len() < INLINE_THRESHOLD {
+            SummaryPayload::Raw(summary.to_string())
+        } else {
+            let compressed = zstd::stream::encode_all(summary.as_bytes(), ZSTD_COMPRESSION_LEVEL)
+                .context("failed to zstd-compress summary")?;
+            SummaryPayload::Zstd(compressed)
+        };
+
+        Ok(Self {
+            payload,
+            model_id,
+            prompt_version: PROMPT_VERSION,
+        })
+    }
+
+    fn decompress(self) -> Result<String> {
+        match self.payload {
+            SummaryPayload::Raw(summary) => Ok(summary),
+            SummaryPayload::Zstd(compressed) => {
+                let decompressed = zstd::stream::decode_all(compressed.as_slice())
+                    .context("failed to zstd-decompress summary")?;
+                String::from_utf8(decompressed).context("decompressed summary was not valid UTF-8")
+            }
+        }
+    }
+
+    /// Whether this entry was produced by a different model or prompt version than the one
+    /// currently configured, meaning the resync worker should re-summarize it.
+    fn is_stale(&self, current_model_id: &str) -> bool {
+        self.model_id != current_model_id || self.prompt_version != PROMPT_VERSION
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct FileDigest {
     pub mtime: Option<SystemTime>,
@@ -83,16 +375,35 @@ pub struct SummaryIndex {
     fs: Arc<dyn Fs>,
     db_connection: heed::Env,
     file_digest_db: heed::Database<Str, SerdeBincode<FileDigest>>, // Key: file path. Val: BLAKE3 digest of its contents.
-    summary_db: heed::Database<SerdeBincode<Blake3Digest>, Str>, // Key: BLAKE3 digest of a file's contents. Val: LLM summary of those contents.
+    summary_db: heed::Database<SerdeBincode<Blake3Digest>, SerdeBincode<StoredSummary>>, // Key: BLAKE3 digest of a file's contents. Val: LLM summary of those contents.
+    ref_count_db: heed::Database<SerdeBincode<Blake3Digest>, SerdeBincode<u64>>, // Key: BLAKE3 digest. Val: how many indexed paths currently have that digest.
+    cursor_db: heed::Database<Str, SerdeBincode<Option<SystemTime>>>, // Key: path handed off for digesting/summarizing but not yet persisted. Val: its mtime.
     backlog: Arc<Mutex<SummaryBacklog>>,
+    // Currently-open and recently-focused paths - see `prioritize_paths`. Entries here skip the
+    // backlog's batching and are drained onto a dedicated high-priority channel as soon as they
+    // need resummarizing.
+    priority_paths: Arc<Mutex<HashSet<Arc<Path>>>>,
     _entry_ids_being_indexed: Arc<IndexingEntrySet>, // TODO can this be removed?
+    _gc_task: Task<()>,
+    _resync_task: Task<()>,
 }
 
 struct Backlogged {
     paths_to_digest: channel::Receiver<Vec<(Arc<Path>, Option<SystemTime>)>>,
+    // Paths from `priority_paths` that need resummarizing, fed into `digest_files` ahead of
+    // `paths_to_digest`.
+    priority_paths_to_digest: channel::Receiver<Vec<(Arc<Path>, Option<SystemTime>)>>,
     task: Task<Result<()>>,
 }
 
+/// The result of checking a single entry against the digest cache: which channel (if any) its
+/// backlog batch should be sent on.
+#[derive(Default)]
+struct BacklogDrain {
+    normal: Vec<(Arc<Path>, Option<SystemTime>)>,
+    priority: Vec<(Arc<Path>, Option<SystemTime>)>,
+}
+
 struct MightNeedSummaryFiles {
     files: channel::Receiver<UnsummarizedFile>,
     task: Task<Result<()>>,
@@ -104,42 +415,464 @@ impl SummaryIndex {
         fs: Arc<dyn Fs>,
         db_connection: heed::Env,
         file_digest_db: heed::Database<Str, SerdeBincode<FileDigest>>,
-        summary_db: heed::Database<SerdeBincode<Blake3Digest>, Str>,
+        summary_db: heed::Database<SerdeBincode<Blake3Digest>, SerdeBincode<StoredSummary>>,
+        ref_count_db: heed::Database<SerdeBincode<Blake3Digest>, SerdeBincode<u64>>,
+        cursor_db: heed::Database<Str, SerdeBincode<Option<SystemTime>>>,
         _entry_ids_being_indexed: Arc<IndexingEntrySet>,
+        cx: &AppContext,
     ) -> Self {
+        SummarizationSettings::register(cx);
+
+        // `ref_count_db` rows are only ever incremented by `persist_summaries`, so a digest that was
+        // already in `file_digest_db` before `ref_count_db` existed (or before this path first
+        // went through the summarize pipeline again) would otherwise start at zero - and look
+        // unreferenced to the GC worker even while live paths still point at it.
+        if let Err(err) = Self::backfill_ref_counts(&db_connection, file_digest_db, ref_count_db) {
+            log::error!("Failed to backfill summary ref counts: {:?}", err);
+        }
+
+        let gc_task = Self::spawn_gc_worker(db_connection.clone(), summary_db, ref_count_db, cx);
+        let resync_task = Self::spawn_resync_worker(
+            db_connection.clone(),
+            file_digest_db,
+            summary_db,
+            fs.clone(),
+            worktree.clone(),
+            cx,
+        );
+        let backlog = Arc::new(Mutex::new(SummaryBacklog::default()));
+        Self::resume_pending_cursor(&db_connection, cursor_db, &backlog);
+
         Self {
             worktree,
             fs,
             db_connection,
             file_digest_db,
             summary_db,
+            ref_count_db,
+            cursor_db,
             _entry_ids_being_indexed,
-            backlog: Default::default(),
+            backlog,
+            priority_paths: Default::default(),
+            _gc_task: gc_task,
+            _resync_task: resync_task,
+        }
+    }
+
+    /// Marks `paths` as high priority, so the next indexing job summarizes them ahead of the
+    /// rest of the backlog instead of waiting behind it. Call this for currently-open and
+    /// recently-focused buffers so the file a user is looking at doesn't wait behind thousands
+    /// of background entries.
+    pub fn prioritize_paths(&self, paths: &[Arc<Path>]) {
+        let mut priority_paths = self.priority_paths.lock();
+        priority_paths.extend(paths.iter().cloned());
+    }
+
+    /// Re-seeds the in-memory backlog with any paths that were handed off for
+    /// digesting/summarizing in a previous run but never made it to `persist_summaries` - e.g.
+    /// because Zed was closed mid-index. This lets a resumed `IndexingJob` pick up where the
+    /// last one left off instead of rescanning the whole worktree.
+    fn resume_pending_cursor(
+        db_connection: &heed::Env,
+        cursor_db: heed::Database<Str, SerdeBincode<Option<SystemTime>>>,
+        backlog: &Arc<Mutex<SummaryBacklog>>,
+    ) {
+        let result = (|| -> Result<()> {
+            let txn = db_connection.read_txn()?;
+            let mut pending = Vec::new();
+            for entry in cursor_db.iter(&txn)? {
+                let (path, mtime) = entry?;
+                pending.push((path.to_string(), mtime));
+            }
+            drop(txn);
+
+            if pending.is_empty() {
+                return Ok(());
+            }
+
+            log::info!(
+                "Resuming indexing job: re-queuing {} path(s) left over from an interrupted run",
+                pending.len()
+            );
+
+            let mut backlog = backlog.lock();
+            for (path, mtime) in pending {
+                backlog.insert(Arc::from(Path::new(&path)), 0, mtime);
+            }
+
+            Ok(())
+        })();
+
+        if let Err(err) = result {
+            log::error!("Failed to resume pending indexing cursor: {:?}", err);
+        }
+    }
+
+    /// Ensures every digest already referenced by `file_digest_db` has a `ref_count_db` row,
+    /// for digests whose ref count was never tracked (e.g. entries persisted before
+    /// `ref_count_db` existed). Only fills in missing rows - a digest that already has a row is
+    /// left alone, since that row is presumably being kept accurate by increments/decrements.
+    fn backfill_ref_counts(
+        db_connection: &heed::Env,
+        digest_db: heed::Database<Str, SerdeBincode<FileDigest>>,
+        ref_count_db: heed::Database<SerdeBincode<Blake3Digest>, SerdeBincode<u64>>,
+    ) -> Result<()> {
+        let mut counts: HashMap<Blake3Digest, u64> = HashMap::default();
+        {
+            let txn = db_connection
+                .read_txn()
+                .context("failed to create read transaction")?;
+            for entry in digest_db.iter(&txn)? {
+                let (_, file_digest) = entry?;
+                *counts.entry(file_digest.digest).or_insert(0) += 1;
+            }
         }
+
+        if counts.is_empty() {
+            return Ok(());
+        }
+
+        let mut txn = db_connection.write_txn()?;
+        let mut backfilled = 0;
+        for (digest, count) in counts {
+            if ref_count_db.get(&txn, &digest)?.is_none() {
+                ref_count_db.put(&mut txn, &digest, &count)?;
+                backfilled += 1;
+            }
+        }
+        txn.commit()?;
+
+        if backfilled > 0 {
+            log::info!(
+                "Backfilled ref counts for {} digest(s) that predated ref counting",
+                backfilled
+            );
+        }
+
+        Ok(())
     }
 
     pub fn file_digest_db(&self) -> heed::Database<Str, SerdeBincode<FileDigest>> {
         self.file_digest_db
     }
 
-    pub fn summary_db(&self) -> heed::Database<SerdeBincode<Blake3Digest>, Str> {
+    pub fn summary_db(
+        &self,
+    ) -> heed::Database<SerdeBincode<Blake3Digest>, SerdeBincode<StoredSummary>> {
         self.summary_db
     }
 
-    pub fn index_entries_changed_on_disk(
-        &self,
+    pub fn ref_count_db(&self) -> heed::Database<SerdeBincode<Blake3Digest>, SerdeBincode<u64>> {
+        self.ref_count_db
+    }
+
+    pub fn cursor_db(&self) -> heed::Database<Str, SerdeBincode<Option<SystemTime>>> {
+        self.cursor_db
+    }
+
+    /// Periodically scans `ref_count_db` for digests whose count has reached zero, and once
+    /// a digest has stayed at zero for longer than `GC_TOMBSTONE_DELAY`, deletes both its
+    /// `summary_db` entry and its ref-count row. A digest that climbs back above zero before
+    /// the delay elapses (e.g. a renamed-then-renamed-back file) is simply forgotten.
+    fn spawn_gc_worker(
+        db_connection: heed::Env,
+        summary_db: heed::Database<SerdeBincode<Blake3Digest>, SerdeBincode<StoredSummary>>,
+        ref_count_db: heed::Database<SerdeBincode<Blake3Digest>, SerdeBincode<u64>>,
+        cx: &AppContext,
+    ) -> Task<()> {
+        let executor = cx.background_executor().clone();
+        executor.clone().spawn(async move {
+            let mut zero_since: HashMap<Blake3Digest, Instant> = HashMap::default();
+
+            loop {
+                executor.timer(GC_INTERVAL).await;
+
+                let result = (|| -> Result<()> {
+                    let mut still_zero = HashSet::new();
+                    let txn = db_connection.read_txn()?;
+                    for entry in ref_count_db.iter(&txn)? {
+                        let (digest, count) = entry?;
+                        if count == 0 {
+                            still_zero.insert(digest);
+                        }
+                    }
+                    drop(txn);
+
+                    // Stop tracking any digest that's no longer at zero.
+                    zero_since.retain(|digest, _| still_zero.contains(digest));
+
+                    let now = Instant::now();
+                    let mut to_delete = Vec::new();
+                    for digest in &still_zero {
+                        let since = *zero_since.entry(*digest).or_insert(now);
+                        if now.duration_since(since) >= GC_TOMBSTONE_DELAY {
+                            to_delete.push(*digest);
+                        }
+                    }
+
+                    if !to_delete.is_empty() {
+                        let mut txn = db_connection.write_txn()?;
+                        let mut deleted = 0;
+                        for digest in &to_delete {
+                            // Another path may have started referencing this digest again
+                            // since we last scanned it - don't delete out from under it.
+                            if ref_count_db.get(&txn, digest)?.unwrap_or(0) == 0 {
+                                summary_db.delete(&mut txn, digest)?;
+                                ref_count_db.delete(&mut txn, digest)?;
+                                zero_since.remove(digest);
+                                deleted += 1;
+                            }
+                        }
+                        txn.commit()?;
+
+                        log::debug!(
+                            "Summary GC deleted {} unreferenced cache entr{}",
+                            deleted,
+                            if deleted == 1 { "y" } else { "ies" }
+                        );
+                    }
+
+                    Ok(())
+                })();
+
+                if let Err(err) = result {
+                    log::error!("Summary GC pass failed: {:?}", err);
+                }
+            }
+        })
+    }
+
+    /// Watches `SummarizationSettings` for changes and, whenever the configured model differs
+    /// from the one that produced a cached summary, re-summarizes that entry with the new model
+    /// at low priority - analogous to Garage's block resync queue. Runs once on startup (to
+    /// catch a model change that happened while Zed was closed) and again every time the
+    /// setting changes.
+    fn spawn_resync_worker(
+        db_connection: heed::Env,
+        file_digest_db: heed::Database<Str, SerdeBincode<FileDigest>>,
+        summary_db: heed::Database<SerdeBincode<Blake3Digest>, SerdeBincode<StoredSummary>>,
+        fs: Arc<dyn Fs>,
+        worktree: Model<Worktree>,
         cx: &AppContext,
-    ) -> impl Future<Output = Result<()>> {
+    ) -> Task<()> {
+        cx.spawn(|cx| async move {
+            let (settings_changed_tx, mut settings_changed_rx) = channel::unbounded();
+            let subscribed = cx.update(|cx| {
+                cx.observe_global::<SettingsStore>(move |_cx| {
+                    settings_changed_tx.try_send(()).ok();
+                })
+                .detach();
+            });
+            if subscribed.is_err() {
+                return; // The app is shutting down.
+            }
+
+            let mut last_model_id = None;
+            loop {
+                let current_model_id =
+                    match cx.update(|cx| SummarizationSettings::get_global(cx).model.clone()) {
+                        Ok(model_id) => model_id,
+                        Err(_) => return, // The app is shutting down.
+                    };
+
+                if last_model_id.as_ref() != Some(&current_model_id) {
+                    last_model_id = Some(current_model_id.clone());
+
+                    let worktree_abs_path =
+                        match cx.update(|cx| worktree.read(cx).abs_path().clone()) {
+                            Ok(abs_path) => abs_path,
+                            Err(_) => return, // The app is shutting down.
+                        };
+
+                    if let Err(err) = Self::resync_stale_summaries(
+                        &db_connection,
+                        file_digest_db,
+                        summary_db,
+                        &fs,
+                        &worktree_abs_path,
+                        &current_model_id,
+                        &cx,
+                    )
+                    .await
+                    {
+                        log::error!("Summary resync pass failed: {:?}", err);
+                    }
+                }
+
+                // Wait for the next settings change, but also re-check periodically in case the
+                // observer above was registered after a change had already landed.
+                select_biased! {
+                    _ = settings_changed_rx.next().fuse() => {}
+                    _ = cx.background_executor().timer(RESYNC_CHECK_INTERVAL).fuse() => {}
+                }
+            }
+        })
+    }
+
+    /// Scans `file_digest_db` for paths whose stored summary was produced by a different model
+    /// or prompt version than `current_model_id`, and re-summarizes each one in place (the
+    /// content digest - and therefore the summary's cache key - doesn't change, only the text
+    /// stored under it does).
+    async fn resync_stale_summaries(
+        db_connection: &heed::Env,
+        file_digest_db: heed::Database<Str, SerdeBincode<FileDigest>>,
+        summary_db: heed::Database<SerdeBincode<Blake3Digest>, SerdeBincode<StoredSummary>>,
+        fs: &Arc<dyn Fs>,
+        worktree_abs_path: &Path,
+        current_model_id: &str,
+        cx: &AsyncAppContext,
+    ) -> Result<()> {
+        let stale_paths = {
+            let txn = db_connection
+                .read_txn()
+                .context("failed to create read transaction")?;
+
+            let mut stale_paths = Vec::new();
+            for entry in file_digest_db.iter(&txn)? {
+                let (db_path, file_digest) = entry?;
+                let Some(stored) = summary_db.get(&txn, &file_digest.digest)? else {
+                    // No summary yet at all - the normal indexing pipeline will produce one.
+                    continue;
+                };
+
+                if stored.is_stale(current_model_id) {
+                    stale_paths.push((db_path.to_string(), file_digest.digest));
+                }
+            }
+            stale_paths
+        };
+
+        if stale_paths.is_empty() {
+            return Ok(());
+        }
+
+        log::info!(
+            "Resyncing {} cached summar{} to model {:?}",
+            stale_paths.len(),
+            if stale_paths.len() == 1 { "y" } else { "ies" },
+            current_model_id
+        );
+
+        for (db_path, digest) in stale_paths {
+            // This is a low-priority background migration, so pace it out rather than
+            // summarizing the whole backlog in one go and competing with normal indexing.
+            cx.background_executor().timer(RESYNC_ENTRY_DELAY).await;
+
+            let entry_abs_path = worktree_abs_path.join(path_for_db_key(&db_path));
+            let Some(contents) = fs
+                .load(&entry_abs_path)
+                .await
+                .with_context(|| format!("failed to read path {entry_abs_path:?}"))
+                .log_err()
+            else {
+                continue;
+            };
+
+            let summary = Self::summarize_file_contents(contents, cx.clone()).await?;
+
+            // As in `summarize_files`, the summary can come back empty if a transient model
+            // failure prevented even a single minimal chunk from summarizing - see
+            // `summarize_file_contents`. Leave the old (stale-tagged) summary in place rather
+            // than overwriting it with an empty one, so this entry stays eligible to be resynced
+            // on the next pass instead of being silently and permanently blanked.
+            if !summary.is_empty() {
+                let stored_summary =
+                    StoredSummary::compress(&summary, current_model_id.to_string())?;
+                let mut txn = db_connection.write_txn()?;
+                summary_db.put(&mut txn, &digest, &stored_summary)?;
+                txn.commit()?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn increment_ref_count(
+        txn: &mut heed::RwTxn<'_>,
+        ref_count_db: heed::Database<SerdeBincode<Blake3Digest>, SerdeBincode<u64>>,
+        digest: &Blake3Digest,
+    ) -> Result<()> {
+        let count = ref_count_db.get(txn, digest)?.unwrap_or(0);
+        ref_count_db.put(txn, digest, &(count + 1))?;
+        Ok(())
+    }
+
+    fn decrement_ref_count(
+        txn: &mut heed::RwTxn<'_>,
+        ref_count_db: heed::Database<SerdeBincode<Blake3Digest>, SerdeBincode<u64>>,
+        digest: &Blake3Digest,
+    ) -> Result<()> {
+        let count = ref_count_db.get(txn, digest)?.unwrap_or(0);
+        ref_count_db.put(txn, digest, &count.saturating_sub(1))?;
+        Ok(())
+    }
+
+    /// Forgets a path entirely: removes its `file_digest_db` entry and decrements the ref
+    /// count of the digest it used to point at, so the GC worker can reclaim the summary
+    /// once nothing else references it.
+    fn remove_path_from_cache(
+        txn: &mut heed::RwTxn<'_>,
+        digest_db: heed::Database<Str, SerdeBincode<FileDigest>>,
+        ref_count_db: heed::Database<SerdeBincode<Blake3Digest>, SerdeBincode<u64>>,
+        db_path: &str,
+    ) -> Result<()> {
+        if let Some(old_digest) = digest_db.get(txn, db_path)?.map(|digest| digest.digest) {
+            Self::decrement_ref_count(txn, ref_count_db, &old_digest)?;
+        }
+        digest_db.delete(txn, db_path)?;
+        Ok(())
+    }
+
+    /// Durably records that `batch` has been handed off to `digest_files`, so that if Zed
+    /// exits before `persist_summaries` commits these paths, the next startup's
+    /// `resume_pending_cursor` can re-queue them instead of relying on a full rescan. Each path
+    /// is its own row, so recording a batch costs O(batch), not O(all pending paths).
+    fn record_cursor_batch(
+        db_connection: &heed::Env,
+        cursor_db: heed::Database<Str, SerdeBincode<Option<SystemTime>>>,
+        batch: &[(Arc<Path>, Option<SystemTime>)],
+    ) -> Result<()> {
+        let mut txn = db_connection.write_txn()?;
+        for (path, mtime) in batch {
+            cursor_db.put(&mut txn, &path.to_string_lossy(), mtime)?;
+        }
+        txn.commit()?;
+        Ok(())
+    }
+
+    /// Clears `path` from the pending cursor once its summary has been durably persisted. Each
+    /// path is its own row, so this is an O(1) delete rather than a scan over every pending path.
+    fn clear_cursor_entry(
+        txn: &mut heed::RwTxn<'_>,
+        cursor_db: heed::Database<Str, SerdeBincode<Option<SystemTime>>>,
+        path: &str,
+    ) -> Result<()> {
+        cursor_db.delete(txn, path)?;
+        Ok(())
+    }
+
+    pub fn index_entries_changed_on_disk(&self, cx: &AppContext) -> IndexingJob {
         let start = Instant::now();
         let worktree = self.worktree.read(cx).snapshot();
         let worktree_abs_path = worktree.abs_path().clone();
-        let backlogged = self.scan_entries(worktree, cx);
-        let digest = self.digest_files(backlogged.paths_to_digest, worktree_abs_path, cx);
-        let needs_summary = self.check_summary_cache(digest.files, cx);
-        let summaries = self.summarize_files(needs_summary.files, cx);
-        let persist = self.persist_summaries(summaries.files, cx);
+        let current_model_id = SummarizationSettings::get_global(cx).model.clone();
+        let (progress, progress_rx) = ProgressTracker::new();
+        let cancellation = CancellationToken::new();
+        let backlogged = self.scan_entries(worktree, progress.clone(), cx);
+        let digest = self.digest_files(
+            backlogged.paths_to_digest,
+            backlogged.priority_paths_to_digest,
+            worktree_abs_path,
+            progress.clone(),
+            cancellation.clone(),
+            cx,
+        );
+        let needs_summary = self.check_summary_cache(digest.files, current_model_id.clone(), cx);
+        let summaries =
+            self.summarize_files(needs_summary.files, progress, cancellation.clone(), cx);
+        let persist = self.persist_summaries(summaries.files, current_model_id, cx);
 
-        async move {
+        let task = cx.background_executor().spawn(async move {
             futures::try_join!(
                 backlogged.task,
                 digest.task,
@@ -154,6 +887,12 @@ impl SummaryIndex {
             );
 
             Ok(())
+        });
+
+        IndexingJob {
+            progress: progress_rx,
+            cancellation,
+            task,
         }
     }
 
@@ -161,18 +900,30 @@ impl SummaryIndex {
         &mut self,
         updated_entries: UpdatedEntriesSet,
         cx: &AppContext,
-    ) -> impl Future<Output = Result<()>> {
+    ) -> IndexingJob {
         let start = Instant::now();
         let worktree = self.worktree.read(cx).snapshot();
         let worktree_abs_path = worktree.abs_path().clone();
-        let backlogged = self.scan_updated_entries(worktree, updated_entries.clone(), cx);
-
-        let digest = self.digest_files(backlogged.paths_to_digest, worktree_abs_path, cx);
-        let needs_summary = self.check_summary_cache(digest.files, cx);
-        let summaries = self.summarize_files(needs_summary.files, cx);
-        let persist = self.persist_summaries(summaries.files, cx);
+        let current_model_id = SummarizationSettings::get_global(cx).model.clone();
+        let (progress, progress_rx) = ProgressTracker::new();
+        let cancellation = CancellationToken::new();
+        let backlogged =
+            self.scan_updated_entries(worktree, updated_entries.clone(), progress.clone(), cx);
+
+        let digest = self.digest_files(
+            backlogged.paths_to_digest,
+            backlogged.priority_paths_to_digest,
+            worktree_abs_path,
+            progress.clone(),
+            cancellation.clone(),
+            cx,
+        );
+        let needs_summary = self.check_summary_cache(digest.files, current_model_id.clone(), cx);
+        let summaries =
+            self.summarize_files(needs_summary.files, progress, cancellation.clone(), cx);
+        let persist = self.persist_summaries(summaries.files, current_model_id, cx);
 
-        async move {
+        let task = cx.background_executor().spawn(async move {
             futures::try_join!(
                 backlogged.task,
                 digest.task,
@@ -184,12 +935,19 @@ impl SummaryIndex {
             log::info!("Summarizing updated entries took {:?}", start.elapsed());
 
             Ok(())
+        });
+
+        IndexingJob {
+            progress: progress_rx,
+            cancellation,
+            task,
         }
     }
 
     fn check_summary_cache(
         &self,
         mut might_need_summary: channel::Receiver<UnsummarizedFile>,
+        current_model_id: String,
         cx: &AppContext,
     ) -> NeedsSummary {
         let db_connection = self.db_connection.clone();
@@ -203,8 +961,14 @@ impl SummaryIndex {
 
                 match db.get(&tx, &file.digest) {
                     Ok(opt_answer) => {
-                        if opt_answer.is_none() {
-                            // It's not in the summary cache db, so we need to summarize it.
+                        // Nothing cached yet, or what's cached was produced by a model/prompt
+                        // we've since moved on from - either way, it needs (re)summarizing.
+                        let needs_summary = match opt_answer {
+                            None => true,
+                            Some(stored) => stored.is_stale(&current_model_id),
+                        };
+
+                        if needs_summary {
                             log::debug!("File {:?} (digest {:?}) was NOT in the db cache and needs to be resummarized.", file.path.display(), &file.digest);
                             needs_summary_tx.send(file).await?;
                         } else {
@@ -212,7 +976,15 @@ impl SummaryIndex {
                         }
                     }
                     Err(err) => {
-                        log::error!("Reading from the summaries database failed: {:?}", err);
+                        // A row that fails to deserialize - e.g. one written in an older
+                        // on-disk format - should be treated the same as a cache miss rather
+                        // than silently dropped, or the file is stuck unsummarized forever.
+                        log::warn!(
+                            "Reading cached summary for digest {:?} failed, treating as a cache miss: {:?}",
+                            &file.digest,
+                            err
+                        );
+                        needs_summary_tx.send(file).await?;
                     }
                 }
             }
@@ -226,42 +998,97 @@ impl SummaryIndex {
         }
     }
 
-    fn scan_entries(&self, worktree: Snapshot, cx: &AppContext) -> Backlogged {
+    fn scan_entries(
+        &self,
+        worktree: Snapshot,
+        progress: ProgressTracker,
+        cx: &AppContext,
+    ) -> Backlogged {
         let (tx, rx) = channel::bounded(512);
+        let (priority_tx, priority_rx) = channel::bounded(512);
         let db_connection = self.db_connection.clone();
         let digest_db = self.file_digest_db;
+        let ref_count_db = self.ref_count_db;
+        let cursor_db = self.cursor_db;
         let backlog = Arc::clone(&self.backlog);
+        let priority_paths = Arc::clone(&self.priority_paths);
         let task = cx.background_executor().spawn(async move {
-            let txn = db_connection
-                .read_txn()
-                .context("failed to create read transaction")?;
-
+            let mut live_paths = HashSet::default();
             for entry in worktree.files(false, 0) {
-                let needs_summary =
-                    Self::add_to_backlog(Arc::clone(&backlog), digest_db, &txn, entry);
+                live_paths.insert(db_key_for_path(&entry.path));
+                progress.file_discovered().await;
+
+                // Scoped to this entry alone - `record_cursor_batch` below opens its own write
+                // transaction, and a read transaction held across the whole scan would pin the
+                // reader and keep LMDB from reclaiming freed pages while those writes commit.
+                let drained = {
+                    let txn = db_connection
+                        .read_txn()
+                        .context("failed to create read transaction")?;
+                    Self::add_to_backlog(
+                        Arc::clone(&backlog),
+                        Arc::clone(&priority_paths),
+                        digest_db,
+                        &txn,
+                        entry,
+                    )
+                };
+
+                if !drained.priority.is_empty() {
+                    Self::record_cursor_batch(&db_connection, cursor_db, &drained.priority)?;
+                    priority_tx.send(drained.priority).await?;
+                }
+
+                if !drained.normal.is_empty() {
+                    Self::record_cursor_batch(&db_connection, cursor_db, &drained.normal)?;
+                    tx.send(drained.normal).await?;
+                }
+            }
+
+            // Anything in file_digest_db that's no longer a live worktree path was deleted
+            // from disk since our last scan - forget it and decrement its digest's ref count.
+            let mut stale_paths = Vec::new();
+            {
+                let txn = db_connection
+                    .read_txn()
+                    .context("failed to create read transaction")?;
 
-                if !needs_summary.is_empty() {
-                    tx.send(needs_summary).await?;
+                for entry in digest_db.iter(&txn)? {
+                    let (db_path, _) = entry?;
+                    if !live_paths.contains(db_path) {
+                        stale_paths.push(db_path.to_string());
+                    }
                 }
             }
 
-            // TODO delete db entries for deleted files
+            if !stale_paths.is_empty() {
+                let mut txn = db_connection.write_txn()?;
+                for db_path in &stale_paths {
+                    Self::remove_path_from_cache(&mut txn, digest_db, ref_count_db, db_path)?;
+                }
+                txn.commit()?;
+            }
 
             Ok(())
         });
 
         Backlogged {
             paths_to_digest: rx,
+            priority_paths_to_digest: priority_rx,
             task,
         }
     }
 
+    /// Checks whether `entry` needs resummarizing and, if so, routes it to the priority batch
+    /// (when it's in `priority_paths`, bypassing the backlog's batching for low latency) or adds
+    /// it to the normal backlog, draining the backlog once it's full.
     fn add_to_backlog(
         backlog: Arc<Mutex<SummaryBacklog>>,
+        priority_paths: Arc<Mutex<HashSet<Arc<Path>>>>,
         digest_db: heed::Database<Str, SerdeBincode<FileDigest>>,
         txn: &RoTxn<'_>,
         entry: &Entry,
-    ) -> Vec<(Arc<Path>, Option<SystemTime>)> {
+    ) -> BacklogDrain {
         let entry_db_key = db_key_for_path(&entry.path);
 
         match digest_db.get(&txn, &entry_db_key) {
@@ -269,12 +1096,26 @@ impl SummaryIndex {
                 // The file path is the same, but the mtime is different. (Or there was no mtime.)
                 // It needs updating, so add it to the backlog! Then, if the backlog is full, drain it and summarize its contents.
                 if entry.mtime != opt_saved_digest.and_then(|digest| digest.mtime) {
+                    // Remove the path once its priority batch is drained, rather than leaving it
+                    // in `priority_paths` forever - otherwise the set would grow unboundedly as
+                    // the user opens files, and a once-focused path would keep bypassing the
+                    // normal backlog on every future change instead of just the next one.
+                    if priority_paths.lock().remove(&entry.path) {
+                        return BacklogDrain {
+                            priority: vec![(Arc::clone(&entry.path), entry.mtime)],
+                            normal: Vec::new(),
+                        };
+                    }
+
                     let mut backlog = backlog.lock();
 
                     backlog.insert(Arc::clone(&entry.path), entry.size, entry.mtime);
 
                     if backlog.needs_drain() {
-                        return backlog.drain().collect();
+                        return BacklogDrain {
+                            normal: backlog.drain().collect(),
+                            priority: Vec::new(),
+                        };
                     }
                 }
             }
@@ -287,24 +1128,27 @@ impl SummaryIndex {
             }
         }
 
-        Vec::new()
+        BacklogDrain::default()
     }
 
     fn scan_updated_entries(
         &self,
         worktree: Snapshot,
         updated_entries: UpdatedEntriesSet,
+        progress: ProgressTracker,
         cx: &AppContext,
     ) -> Backlogged {
         let (tx, rx) = channel::bounded(512);
+        let (priority_tx, priority_rx) = channel::bounded(512);
         // let (deleted_entry_ranges_tx, deleted_entry_ranges_rx) = channel::bounded(128);
         let db_connection = self.db_connection.clone();
         let digest_db = self.file_digest_db;
+        let ref_count_db = self.ref_count_db;
+        let cursor_db = self.cursor_db;
         let backlog = Arc::clone(&self.backlog);
+        let priority_paths = Arc::clone(&self.priority_paths);
         let task = cx.background_executor().spawn(async move {
-            let txn = db_connection
-                .read_txn()
-                .context("failed to create read transaction")?;
+            let mut removed_paths = Vec::new();
 
             for (path, entry_id, status) in updated_entries.iter() {
                 match status {
@@ -314,34 +1158,65 @@ impl SummaryIndex {
                     | project::PathChange::AddedOrUpdated => {
                         if let Some(entry) = worktree.entry_for_id(*entry_id) {
                             if entry.is_file() {
-                                let needs_summary = Self::add_to_backlog(
-                                    Arc::clone(&backlog),
-                                    digest_db,
-                                    &txn,
-                                    entry,
-                                );
-
-                                if !needs_summary.is_empty() {
-                                    tx.send(needs_summary).await?;
+                                progress.file_discovered().await;
+
+                                // Scoped to this entry alone - `record_cursor_batch` below opens
+                                // its own write transaction, and a read transaction held across
+                                // the whole scan would pin the reader and keep LMDB from
+                                // reclaiming freed pages while those writes commit.
+                                let drained = {
+                                    let txn = db_connection
+                                        .read_txn()
+                                        .context("failed to create read transaction")?;
+                                    Self::add_to_backlog(
+                                        Arc::clone(&backlog),
+                                        Arc::clone(&priority_paths),
+                                        digest_db,
+                                        &txn,
+                                        entry,
+                                    )
+                                };
+
+                                if !drained.priority.is_empty() {
+                                    Self::record_cursor_batch(
+                                        &db_connection,
+                                        cursor_db,
+                                        &drained.priority,
+                                    )?;
+                                    priority_tx.send(drained.priority).await?;
+                                }
+
+                                if !drained.normal.is_empty() {
+                                    Self::record_cursor_batch(
+                                        &db_connection,
+                                        cursor_db,
+                                        &drained.normal,
+                                    )?;
+                                    tx.send(drained.normal).await?;
                                 }
                             }
                         }
                     }
                     project::PathChange::Removed => {
-                        let _db_path = db_key_for_path(path);
-                        // TODO delete db entries for deleted files
-                        // deleted_entry_ranges_tx
-                        //     .send((Bound::Included(db_path.clone()), Bound::Included(db_path)))
-                        //     .await?;
+                        removed_paths.push(db_key_for_path(path));
                     }
                 }
             }
 
+            if !removed_paths.is_empty() {
+                let mut txn = db_connection.write_txn()?;
+                for db_path in &removed_paths {
+                    Self::remove_path_from_cache(&mut txn, digest_db, ref_count_db, db_path)?;
+                }
+                txn.commit()?;
+            }
+
             Ok(())
         });
 
         Backlogged {
             paths_to_digest: rx,
+            priority_paths_to_digest: priority_rx,
             // deleted_entry_ranges: deleted_entry_ranges_rx,
             task,
         }
@@ -350,7 +1225,10 @@ impl SummaryIndex {
     fn digest_files(
         &self,
         paths: channel::Receiver<Vec<(Arc<Path>, Option<SystemTime>)>>,
+        priority_paths: channel::Receiver<Vec<(Arc<Path>, Option<SystemTime>)>>,
         worktree_abs_path: Arc<Path>,
+        progress: ProgressTracker,
+        cancellation: CancellationToken,
         cx: &AppContext,
     ) -> MightNeedSummaryFiles {
         let fs = self.fs.clone();
@@ -359,10 +1237,58 @@ impl SummaryIndex {
             cx.background_executor()
                 .scoped(|cx| {
                     for _ in 0..cx.num_cpus() {
+                        let progress = progress.clone();
+                        let cancellation = cancellation.clone();
                         cx.spawn(async {
-                            while let Ok(pairs) = paths.recv().await {
+                            let mut priority_open = true;
+                            let mut normal_open = true;
+                            while priority_open || normal_open {
+                                // Always prefer a batch of priority paths - e.g. the buffers a
+                                // user currently has open - over the normal backlog, so they get
+                                // summarized first.
+                                let priority_recv = if priority_open {
+                                    Either::Right(priority_paths.recv())
+                                } else {
+                                    Either::Left(future::pending())
+                                };
+                                let normal_recv = if normal_open {
+                                    Either::Right(paths.recv())
+                                } else {
+                                    Either::Left(future::pending())
+                                };
+
+                                let pairs = select_biased! {
+                                    result = priority_recv.fuse() => {
+                                        match result {
+                                            Ok(pairs) => Some(pairs),
+                                            Err(_) => {
+                                                priority_open = false;
+                                                None
+                                            }
+                                        }
+                                    }
+                                    result = normal_recv.fuse() => {
+                                        match result {
+                                            Ok(pairs) => Some(pairs),
+                                            Err(_) => {
+                                                normal_open = false;
+                                                None
+                                            }
+                                        }
+                                    }
+                                };
+
+                                let Some(pairs) = pairs else { continue };
+
                                 // Note: we could process all these files concurrently if desired. Might or might not speed things up.
                                 for (path, mtime) in pairs {
+                                    if cancellation.is_cancelled() {
+                                        log::info!(
+                                            "Indexing job cancelled - stopping digest_files early"
+                                        );
+                                        return;
+                                    }
+
                                     let entry_abs_path = worktree_abs_path.join(&path);
 
                                     // Load the file's contents and compute its hash digest.
@@ -384,6 +1310,8 @@ impl SummaryIndex {
                                             hasher.finalize().to_hex()
                                         };
 
+                                        progress.file_hashed(contents.len() as u64).await;
+
                                         UnsummarizedFile {
                                             digest,
                                             contents,
@@ -416,25 +1344,37 @@ impl SummaryIndex {
     fn summarize_files(
         &self,
         mut unsummarized_files: channel::Receiver<UnsummarizedFile>,
+        progress: ProgressTracker,
+        cancellation: CancellationToken,
         cx: &AppContext,
     ) -> SummarizeFiles {
         let (summarized_tx, summarized_rx) = channel::bounded(512);
         let task = cx.spawn(|cx| async move {
             while let Some(file) = unsummarized_files.next().await {
-                log::debug!("Summarizing {:?}", file);
-                let summary = cx
-                    .update(|cx| Self::summarize_code(&file.contents, cx))?
-                    .await?;
+                if cancellation.is_cancelled() {
+                    log::info!("Indexing job cancelled - stopping summarize_files early");
+                    break;
+                }
 
-                // Note that the summary could be empty because of an error talking to a cloud provider,
-                // e.g. because the context limit was exceeded. In that case, we return Ok(String::new()).
+                log::debug!("Summarizing {:?}", file);
+                let UnsummarizedFile {
+                    path,
+                    mtime,
+                    digest,
+                    contents,
+                } = file;
+                let summary = Self::summarize_file_contents(contents, cx.clone()).await?;
+                progress.summary_completed().await;
+
+                // Note that the summary can be empty if even a single, minimal (unsplittable)
+                // chunk of the file failed to summarize - see summarize_file_contents.
                 if !summary.is_empty() {
                     summarized_tx
                         .send(SummarizedFile {
-                            path: file.path.display().to_string(),
-                            digest: file.digest,
+                            path,
+                            digest,
                             summary,
-                            mtime: file.mtime,
+                            mtime,
                         })
                         .await?
                 }
@@ -449,9 +1389,110 @@ impl SummaryIndex {
         }
     }
 
+    /// Summarizes an entire file's contents, transparently splitting it into a map-reduce
+    /// pipeline when it's too large for a single prompt: the file is split into line-bounded,
+    /// overlapping chunks that are summarized concurrently, and the resulting partial
+    /// summaries are combined by `reduce_summaries`. Only falls back to an empty summary when
+    /// a single, minimal (unsplittable) chunk fails to summarize - see `complete_summarization_prompt`.
+    fn summarize_file_contents(
+        contents: String,
+        cx: AsyncAppContext,
+    ) -> BoxFuture<'static, Result<String>> {
+        async move {
+            let chunks = split_into_chunks(&contents, CHUNK_BYTE_BUDGET, CHUNK_OVERLAP_LINES);
+            if chunks.len() <= 1 {
+                return cx.update(|cx| Self::summarize_code(&contents, cx))?.await;
+            }
+
+            log::debug!(
+                "File is {} bytes, splitting into {} chunks for map-reduce summarization",
+                contents.len(),
+                chunks.len()
+            );
+
+            let chunk_summaries = cx.update(|cx| {
+                chunks
+                    .iter()
+                    .map(|chunk| Self::summarize_code(chunk, cx))
+                    .collect::<Vec<_>>()
+            })?;
+            let partial_summaries = futures::future::join_all(chunk_summaries)
+                .await
+                .into_iter()
+                .collect::<Result<Vec<_>>>()?;
+
+            Self::reduce_summaries(partial_summaries, cx).await
+        }
+        .boxed()
+    }
+
+    /// Combines partial summaries into a single one, recursing in a balanced tree when the
+    /// concatenated partials are themselves too large for one "reduce" prompt. Bottoms out at
+    /// two summaries: if they're still over budget at that point, sends them anyway (truncated)
+    /// rather than splitting further, since splitting two summaries can't make the problem any
+    /// smaller.
+    fn reduce_summaries(
+        summaries: Vec<String>,
+        cx: AsyncAppContext,
+    ) -> BoxFuture<'static, Result<String>> {
+        async move {
+            if summaries.len() == 1 {
+                return Ok(summaries.into_iter().next().unwrap());
+            }
+
+            let combined = summaries.join("\n");
+            if combined.len() <= CHUNK_BYTE_BUDGET {
+                return cx
+                    .update(|cx| Self::summarize_combined_summaries(&combined, cx))?
+                    .await;
+            }
+
+            if summaries.len() <= 2 {
+                // Can't split this any further - splitting two summaries gives two
+                // single-summary halves, each of which returns itself verbatim (the
+                // `len() == 1` case above), which would just re-enter this same branch
+                // forever. Send it as the reduce prompt anyway, truncated to fit.
+                let truncated = truncate_to_byte_budget(&combined, CHUNK_BYTE_BUDGET);
+                return cx
+                    .update(|cx| Self::summarize_combined_summaries(truncated, cx))?
+                    .await;
+            }
+
+            let mid = summaries.len() / 2;
+            let (left, right) = summaries.split_at(mid);
+            let (left_summary, right_summary) = futures::try_join!(
+                Self::reduce_summaries(left.to_vec(), cx.clone()),
+                Self::reduce_summaries(right.to_vec(), cx.clone()),
+            )?;
+
+            Self::reduce_summaries(vec![left_summary, right_summary], cx).await
+        }
+        .boxed()
+    }
+
     fn summarize_code(code: &str, cx: &AppContext) -> impl Future<Output = Result<String>> {
+        const PROMPT_BEFORE_CODE: &str = "Summarize this code in 3 sentences, using no newlines or bullet points in the summary:";
+
+        Self::complete_summarization_prompt(PROMPT_BEFORE_CODE, code, cx)
+    }
+
+    fn summarize_combined_summaries(
+        summaries: &str,
+        cx: &AppContext,
+    ) -> impl Future<Output = Result<String>> {
+        const PROMPT_BEFORE_SUMMARIES: &str = "Combine these partial summaries of one file into a single 3-sentence summary, using no newlines or bullet points in the summary:";
+
+        Self::complete_summarization_prompt(PROMPT_BEFORE_SUMMARIES, summaries, cx)
+    }
+
+    fn complete_summarization_prompt(
+        prompt_before_text: &'static str,
+        text: &str,
+        cx: &AppContext,
+    ) -> impl Future<Output = Result<String>> {
         let start = Instant::now();
-        let summary_model_name: LanguageModelName = "gpt-4o-mini".to_string().into(); // TODO read this from the user's settings.
+        let summary_model_name: LanguageModelName =
+            SummarizationSettings::get_global(cx).model.clone().into();
         let Some(model) = LanguageModelRegistry::read_global(cx)
             .available_models(cx)
             .find(|model| model.name() == summary_model_name)
@@ -460,8 +1501,7 @@ impl SummaryIndex {
                 Err(anyhow!("Couldn't find the preferred summarization model ({:?}) in the language registry's available models", summary_model_name))
             });
         };
-        const PROMPT_BEFORE_CODE: &str = "Summarize this code in 3 sentences, using no newlines or bullet points in the summary:";
-        let prompt = format!("{PROMPT_BEFORE_CODE}\n{code}");
+        let prompt = format!("{prompt_before_text}\n{text}");
 
         log::debug!(
             "Summarizing code by sending this prompt to {:?}: {:?}",
@@ -478,7 +1518,7 @@ impl SummaryIndex {
             temperature: 1.0,
         };
 
-        let code_len = code.len();
+        let text_len = text.len();
         let stream =
             LanguageModelCompletionProvider::read_global(cx).complete_bg(request, model, cx);
 
@@ -486,9 +1526,9 @@ impl SummaryIndex {
             match stream.await {
                 Ok(answer) => {
                     log::info!(
-                        "It took {:?} to summarize {:?} bytes of code.",
+                        "It took {:?} to summarize {:?} bytes.",
                         start.elapsed(),
-                        code_len
+                        text_len
                     );
 
                     log::debug!("Summary was: {:?}", &answer);
@@ -496,10 +1536,10 @@ impl SummaryIndex {
                     Ok(answer)
                 }
                 Err(e) => {
-                    // Log a warning because we'll continue anyway.
-                    // In the future, we may want to try splitting it up into multiple requests and concatenating the summaries,
-                    // but this might give bad summaries due to cutting off source code files in the middle.
-                    log::warn!("Failed to summarize {code_len} bytes of code: {:?}", e);
+                    // Log a warning because we'll continue anyway. This is the finest granularity
+                    // we split summarization requests into (one map-reduce chunk, or one reduce
+                    // step), so there's nothing smaller left to retry with.
+                    log::warn!("Failed to summarize {text_len} bytes: {:?}", e);
 
                     Ok(String::new())
                 }
@@ -510,11 +1550,14 @@ impl SummaryIndex {
     fn persist_summaries(
         &self,
         summaries: channel::Receiver<SummarizedFile>,
+        current_model_id: String,
         cx: &AppContext,
     ) -> Task<Result<()>> {
         let db_connection = self.db_connection.clone();
         let digest_db = self.file_digest_db;
         let summary_db = self.summary_db;
+        let ref_count_db = self.ref_count_db;
+        let cursor_db = self.cursor_db;
         cx.background_executor().spawn(async move {
             let mut summaries = summaries.chunks_timeout(4096, Duration::from_secs(2));
             while let Some(summaries) = summaries.next().await {
@@ -525,15 +1568,31 @@ impl SummaryIndex {
                         file.summary.len(),
                         file.digest
                     );
+
+                    // `file_digest_db` is keyed by `db_key_for_path`, the same encoding
+                    // `scan_entries`/`scan_updated_entries` use - not the path's own display
+                    // form, which is also what `cursor_db` tracks it under below.
+                    let db_key = db_key_for_path(&file.path);
+                    let old_digest = digest_db.get(&txn, &db_key)?.map(|digest| digest.digest);
+                    if old_digest != Some(file.digest) {
+                        if let Some(old_digest) = old_digest {
+                            Self::decrement_ref_count(&mut txn, ref_count_db, &old_digest)?;
+                        }
+                        Self::increment_ref_count(&mut txn, ref_count_db, &file.digest)?;
+                    }
+
                     digest_db.put(
                         &mut txn,
-                        &file.path,
+                        &db_key,
                         &FileDigest {
                             mtime: file.mtime,
                             digest: file.digest,
                         },
                     )?;
-                    summary_db.put(&mut txn, &file.digest, &file.summary)?;
+                    let stored_summary =
+                        StoredSummary::compress(&file.summary, current_model_id.clone())?;
+                    summary_db.put(&mut txn, &file.digest, &stored_summary)?;
+                    Self::clear_cursor_entry(&mut txn, cursor_db, &file.path.to_string_lossy())?;
                 }
                 txn.commit()?;
 
@@ -548,4 +1607,365 @@ impl SummaryIndex {
 
 fn db_key_for_path(path: &Arc<Path>) -> String {
     path.to_string_lossy().replace('/', "\0")
-}
\ No newline at end of file
+}
+
+/// The inverse of `db_key_for_path` - turns a `file_digest_db` key back into a joinable relative
+/// path. Used by the resync worker, which only has the stored key to go on.
+fn path_for_db_key(db_key: &str) -> PathBuf {
+    PathBuf::from(db_key.replace('\0', "/"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn open_test_dbs() -> (
+        tempfile::TempDir,
+        heed::Env,
+        heed::Database<Str, SerdeBincode<FileDigest>>,
+        heed::Database<SerdeBincode<Blake3Digest>, SerdeBincode<u64>>,
+    ) {
+        let dir = tempfile::tempdir().unwrap();
+        let env = unsafe {
+            heed::EnvOpenOptions::new()
+                .map_size(10 * 1024 * 1024)
+                .max_dbs(8)
+                .open(dir.path())
+                .unwrap()
+        };
+        let mut txn = env.write_txn().unwrap();
+        let digest_db = env
+            .create_database::<Str, SerdeBincode<FileDigest>>(&mut txn, Some("file_digest"))
+            .unwrap();
+        let ref_count_db = env
+            .create_database::<SerdeBincode<Blake3Digest>, SerdeBincode<u64>>(
+                &mut txn,
+                Some("ref_count"),
+            )
+            .unwrap();
+        txn.commit().unwrap();
+        (dir, env, digest_db, ref_count_db)
+    }
+
+    fn digest_of(byte: u8) -> Blake3Digest {
+        let mut hasher = blake3::Hasher::new();
+        hasher.update(&[byte]);
+        hasher.finalize().to_hex()
+    }
+
+    /// Regression test for `reduce_summaries` recursing forever when two oversized partial
+    /// summaries can't be split any smaller. `truncate_to_byte_budget` is what lets it send the
+    /// reduce prompt anyway instead of re-splitting into the same two summaries.
+    #[test]
+    fn truncate_to_byte_budget_terminates_oversized_pair() {
+        let left = "x".repeat(CHUNK_BYTE_BUDGET);
+        let right = "y".repeat(CHUNK_BYTE_BUDGET);
+        let combined = format!("{left}\n{right}");
+        assert!(combined.len() > CHUNK_BYTE_BUDGET);
+
+        let truncated = truncate_to_byte_budget(&combined, CHUNK_BYTE_BUDGET);
+        assert!(truncated.len() <= CHUNK_BYTE_BUDGET);
+        assert!(combined.starts_with(truncated));
+    }
+
+    #[test]
+    fn truncate_to_byte_budget_keeps_char_boundary() {
+        // 'é' is 2 bytes, so a budget that lands inside it must back off by one byte.
+        let text = "é";
+        let truncated = truncate_to_byte_budget(text, 1);
+        assert_eq!(truncated, "");
+    }
+
+    #[test]
+    fn db_key_for_path_round_trips() {
+        let path: Arc<Path> = Arc::from(Path::new("src/foo/bar.rs"));
+        let key = db_key_for_path(&path);
+        assert_eq!(path_for_db_key(&key), Path::new("src/foo/bar.rs"));
+    }
+
+    /// Regression test for the keys `persist_summaries` writes under (`db_key_for_path`) not
+    /// matching what a rescan compares them against - a mismatch there makes every persisted
+    /// entry look deleted on the next scan.
+    #[test]
+    fn rescan_with_unchanged_files_deletes_nothing() {
+        let (_dir, env, digest_db, ref_count_db) = open_test_dbs();
+        let path: Arc<Path> = Arc::from(Path::new("src/foo.rs"));
+        let digest = digest_of(1);
+        let db_key = db_key_for_path(&path);
+
+        {
+            let mut txn = env.write_txn().unwrap();
+            digest_db
+                .put(
+                    &mut txn,
+                    &db_key,
+                    &FileDigest {
+                        mtime: None,
+                        digest,
+                    },
+                )
+                .unwrap();
+            ref_count_db.put(&mut txn, &digest, &1).unwrap();
+            txn.commit().unwrap();
+        }
+
+        // Re-derive "live" paths the same way `scan_entries` does for an unchanged worktree.
+        let mut live_paths = HashSet::default();
+        live_paths.insert(db_key_for_path(&path));
+
+        let mut stale_paths = Vec::new();
+        {
+            let txn = env.read_txn().unwrap();
+            for entry in digest_db.iter(&txn).unwrap() {
+                let (entry_db_path, _) = entry.unwrap();
+                if !live_paths.contains(entry_db_path) {
+                    stale_paths.push(entry_db_path.to_string());
+                }
+            }
+        }
+        assert!(
+            stale_paths.is_empty(),
+            "unchanged file was misclassified as deleted: {stale_paths:?}"
+        );
+
+        let txn = env.read_txn().unwrap();
+        assert_eq!(ref_count_db.get(&txn, &digest).unwrap(), Some(1));
+        assert!(digest_db.get(&txn, &db_key).unwrap().is_some());
+    }
+
+    /// Regression test for `remove_path_from_cache` silently no-op'ing when the key it's asked
+    /// to remove doesn't match the key the entry was actually stored under.
+    #[test]
+    fn removing_one_of_two_identical_files_decrements_ref_count() {
+        let (_dir, env, digest_db, ref_count_db) = open_test_dbs();
+        let digest = digest_of(7);
+        let key_a = db_key_for_path(&Arc::from(Path::new("a.rs")));
+        let key_b = db_key_for_path(&Arc::from(Path::new("b.rs")));
+
+        {
+            let mut txn = env.write_txn().unwrap();
+            digest_db
+                .put(
+                    &mut txn,
+                    &key_a,
+                    &FileDigest {
+                        mtime: None,
+                        digest,
+                    },
+                )
+                .unwrap();
+            digest_db
+                .put(
+                    &mut txn,
+                    &key_b,
+                    &FileDigest {
+                        mtime: None,
+                        digest,
+                    },
+                )
+                .unwrap();
+            ref_count_db.put(&mut txn, &digest, &2).unwrap();
+            txn.commit().unwrap();
+        }
+
+        {
+            let mut txn = env.write_txn().unwrap();
+            SummaryIndex::remove_path_from_cache(&mut txn, digest_db, ref_count_db, &key_a)
+                .unwrap();
+            txn.commit().unwrap();
+        }
+
+        let txn = env.read_txn().unwrap();
+        assert_eq!(ref_count_db.get(&txn, &digest).unwrap(), Some(1));
+        assert!(digest_db.get(&txn, &key_a).unwrap().is_none());
+        assert!(digest_db.get(&txn, &key_b).unwrap().is_some());
+    }
+
+    /// Regression test for `ref_count_db` rows never being backfilled for digests that were
+    /// already in `file_digest_db` before ref counting existed - without the backfill, removing
+    /// one of two such deduped paths would drop the count straight to zero and the GC worker
+    /// would reclaim a summary the other path still references.
+    #[test]
+    fn backfill_ref_counts_covers_pre_existing_digests() {
+        let (_dir, env, digest_db, ref_count_db) = open_test_dbs();
+        let digest = digest_of(3);
+        let key_a = db_key_for_path(&Arc::from(Path::new("a.rs")));
+        let key_b = db_key_for_path(&Arc::from(Path::new("b.rs")));
+
+        // Two paths already share a digest, but (as if persisted before ref counting existed)
+        // `ref_count_db` has no row for it yet.
+        {
+            let mut txn = env.write_txn().unwrap();
+            digest_db
+                .put(
+                    &mut txn,
+                    &key_a,
+                    &FileDigest {
+                        mtime: None,
+                        digest,
+                    },
+                )
+                .unwrap();
+            digest_db
+                .put(
+                    &mut txn,
+                    &key_b,
+                    &FileDigest {
+                        mtime: None,
+                        digest,
+                    },
+                )
+                .unwrap();
+            txn.commit().unwrap();
+        }
+
+        SummaryIndex::backfill_ref_counts(&env, digest_db, ref_count_db).unwrap();
+
+        let txn = env.read_txn().unwrap();
+        assert_eq!(ref_count_db.get(&txn, &digest).unwrap(), Some(2));
+        drop(txn);
+
+        // Removing one path should now land on 1, not 0.
+        let mut txn = env.write_txn().unwrap();
+        SummaryIndex::remove_path_from_cache(&mut txn, digest_db, ref_count_db, &key_a).unwrap();
+        txn.commit().unwrap();
+
+        let txn = env.read_txn().unwrap();
+        assert_eq!(ref_count_db.get(&txn, &digest).unwrap(), Some(1));
+    }
+
+    /// A digest that already has a ref count row is left alone by the backfill, since that row
+    /// is presumably being kept accurate by the normal increment/decrement path already.
+    #[test]
+    fn backfill_ref_counts_does_not_clobber_existing_rows() {
+        let (_dir, env, digest_db, ref_count_db) = open_test_dbs();
+        let digest = digest_of(9);
+        let key_a = db_key_for_path(&Arc::from(Path::new("a.rs")));
+
+        {
+            let mut txn = env.write_txn().unwrap();
+            digest_db
+                .put(
+                    &mut txn,
+                    &key_a,
+                    &FileDigest {
+                        mtime: None,
+                        digest,
+                    },
+                )
+                .unwrap();
+            // Pretend some other path also referenced this digest before it was renamed away,
+            // so the tracked count (5) is higher than what `file_digest_db` alone would imply.
+            ref_count_db.put(&mut txn, &digest, &5).unwrap();
+            txn.commit().unwrap();
+        }
+
+        SummaryIndex::backfill_ref_counts(&env, digest_db, ref_count_db).unwrap();
+
+        let txn = env.read_txn().unwrap();
+        assert_eq!(ref_count_db.get(&txn, &digest).unwrap(), Some(5));
+    }
+
+    /// Regression test for the `summary_db` value codec change from `Str` to
+    /// `SerdeBincode<StoredSummary>`: a row written in the old raw-string format must fail to
+    /// deserialize under the new codec, which is exactly the condition `check_summary_cache`
+    /// needs to treat as "needs (re)summarize" rather than silently dropping the file.
+    #[test]
+    fn pre_migration_raw_row_fails_to_deserialize_as_stored_summary() {
+        let dir = tempfile::tempdir().unwrap();
+        let env = unsafe {
+            heed::EnvOpenOptions::new()
+                .map_size(10 * 1024 * 1024)
+                .max_dbs(8)
+                .open(dir.path())
+                .unwrap()
+        };
+        let digest = digest_of(4);
+
+        let mut txn = env.write_txn().unwrap();
+        let raw_db = env
+            .create_database::<SerdeBincode<Blake3Digest>, Str>(&mut txn, Some("summary"))
+            .unwrap();
+        raw_db
+            .put(&mut txn, &digest, "a pre-migration raw summary")
+            .unwrap();
+        txn.commit().unwrap();
+
+        let txn = env.read_txn().unwrap();
+        let summary_db = env
+            .open_database::<SerdeBincode<Blake3Digest>, SerdeBincode<StoredSummary>>(
+                &txn,
+                Some("summary"),
+            )
+            .unwrap()
+            .unwrap();
+        assert!(summary_db.get(&txn, &digest).is_err());
+    }
+
+    fn open_test_cursor_db() -> (
+        tempfile::TempDir,
+        heed::Env,
+        heed::Database<Str, SerdeBincode<Option<SystemTime>>>,
+    ) {
+        let dir = tempfile::tempdir().unwrap();
+        let env = unsafe {
+            heed::EnvOpenOptions::new()
+                .map_size(10 * 1024 * 1024)
+                .max_dbs(8)
+                .open(dir.path())
+                .unwrap()
+        };
+        let mut txn = env.write_txn().unwrap();
+        let cursor_db = env
+            .create_database::<Str, SerdeBincode<Option<SystemTime>>>(&mut txn, Some("cursor"))
+            .unwrap();
+        txn.commit().unwrap();
+        (dir, env, cursor_db)
+    }
+
+    /// Regression test for the `cursor_db` schema change from a single vec-valued row to
+    /// per-path rows: `record_cursor_batch` must add rows without disturbing unrelated paths,
+    /// and `clear_cursor_entry` must remove exactly the path it's given, in O(1), rather than
+    /// requiring a `retain` over every pending path.
+    #[test]
+    fn cursor_batch_add_and_clear_are_per_path() {
+        let (_dir, env, cursor_db) = open_test_cursor_db();
+
+        let batch = vec![
+            (Arc::from(Path::new("a.rs")), None),
+            (Arc::from(Path::new("b.rs")), None),
+        ];
+        SummaryIndex::record_cursor_batch(&env, cursor_db, &batch).unwrap();
+
+        let mut txn = env.write_txn().unwrap();
+        SummaryIndex::clear_cursor_entry(&mut txn, cursor_db, "a.rs").unwrap();
+        txn.commit().unwrap();
+
+        let txn = env.read_txn().unwrap();
+        assert_eq!(cursor_db.get(&txn, "a.rs").unwrap(), None);
+        assert_eq!(cursor_db.get(&txn, "b.rs").unwrap(), Some(None));
+    }
+
+    /// Regression test for `resume_pending_cursor` iterating all rows in `cursor_db`: every
+    /// independently-stored pending path must be picked up, not just the last one written.
+    #[test]
+    fn cursor_db_iteration_finds_every_pending_path() {
+        let (_dir, env, cursor_db) = open_test_cursor_db();
+
+        let batch = vec![
+            (Arc::from(Path::new("a.rs")), None),
+            (Arc::from(Path::new("b.rs")), None),
+            (Arc::from(Path::new("c.rs")), None),
+        ];
+        SummaryIndex::record_cursor_batch(&env, cursor_db, &batch).unwrap();
+
+        let txn = env.read_txn().unwrap();
+        let mut pending: Vec<_> = cursor_db
+            .iter(&txn)
+            .unwrap()
+            .map(|entry| entry.unwrap().0.to_string())
+            .collect();
+        pending.sort();
+        assert_eq!(pending, vec!["a.rs", "b.rs", "c.rs"]);
+    }
+}